@@ -0,0 +1,199 @@
+//! Module for deterministically allocating node addresses within a network's subnet.
+//!
+//! Each node's address is derived from a local, per-node identity (see
+//! [`network_config::local_node_identity`](../network_config/fn.local_node_identity.html)),
+//! hashed and reduced into the host-address range of the network's `network_addr`/`cidr`,
+//! similar to how innernet assigns peer IPs. A network's `NetworkKey` is a single shared secret
+//! held identically by every node on it, so it cannot be used as the allocation seed without
+//! every node colliding on the same address; the per-node identity exists precisely to give each
+//! node a distinct seed. This keeps allocation stateless from a single node's perspective: given
+//! the same identity and subnet, the candidate address is always the same, with collisions
+//! resolved by probing forward through the already-claimed set.
+
+extern crate error_chain;
+extern crate crypto;
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use crate::network_config::NetworkConfig;
+
+mod errors {
+    error_chain!{}
+}
+
+use errors::*;
+
+/// Number of address bits in an IPv4 address.
+const IPV4_BITS: u32 = 32;
+/// Number of address bits in an IPv6 address.
+const IPV6_BITS: u32 = 128;
+
+/// Hash a node identity down to a u128, used as the seed for candidate host offsets.
+fn hash_node_identity(node_identity: &[u8]) -> u128 {
+    let mut hasher = Sha256::new();
+    hasher.input(node_identity);
+
+    let mut digest = [0u8; 32];
+    hasher.result(&mut digest);
+
+    let mut seed = [0u8; 16];
+    seed.copy_from_slice(&digest[..16]);
+    u128::from_be_bytes(seed)
+}
+
+/// Convert a network address to its integer representation, alongside its total bit width.
+fn addr_to_u128(addr: IpAddr) -> (u128, u32) {
+    match addr {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, IPV4_BITS),
+        IpAddr::V6(v6) => (u128::from(v6), IPV6_BITS),
+    }
+}
+
+/// Convert an integer representation back to an `IpAddr` of the same family as `bits` implies.
+fn u128_to_addr(value: u128, bits: u32) -> IpAddr {
+    if bits == IPV4_BITS {
+        IpAddr::V4(Ipv4Addr::from(value as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(value))
+    }
+}
+
+/// Allocate a deterministic host address for `node_identity` within `network`'s subnet, avoiding
+/// addresses already present in `claimed`.
+///
+/// `node_identity` must be distinct per node (see
+/// [`network_config::local_node_identity`](../network_config/fn.local_node_identity.html)); it is
+/// not `network.key.access_key`, which every node on the network shares and so would make every
+/// node collide on the same candidate address.
+///
+/// The candidate address is derived by hashing `node_identity` and reducing it modulo the number
+/// of usable host addresses in the subnet (excluding the network and broadcast addresses). If
+/// that candidate is already claimed, later host offsets are probed in order until a free one is
+/// found or the subnet is exhausted.
+pub fn allocate_address(network: &NetworkConfig, node_identity: &[u8], claimed: &HashSet<IpAddr>) -> Result<IpAddr> {
+    let (network_value, bits) = addr_to_u128(network.network_addr);
+
+    // `cidr` comes straight off a possibly hand-edited or pre-validation config (`Deserialize`
+    // does no range checking), so it isn't guaranteed to fit `network.network_addr`'s family.
+    // Use checked arithmetic rather than `bits - network.cidr`, which underflows for a `cidr`
+    // wider than the address, and `1u128 << host_bits`, which overflows when `host_bits` is a
+    // full 128 bits (an IPv6 `cidr` of 0).
+    let host_bits = bits.checked_sub(u32::from(network.cidr))
+        .ok_or_else(|| format!("CIDR /{} is not valid for {}", network.cidr, network.network_addr))?;
+    let host_count: u128 = 1u128.checked_shl(host_bits)
+        .ok_or_else(|| format!("CIDR /{} is not valid for {}", network.cidr, network.network_addr))?;
+    if host_bits < 2 {
+        bail!("Subnet /{} has no usable host addresses", network.cidr);
+    }
+
+    // `network_addr` may carry host bits (e.g. `192.168.1.1/24`); mask it down to the subnet's
+    // network address so every offset below lands inside the subnet.
+    let network_value = network_value & !(host_count - 1);
+
+    // Usable host offsets run from 1 (skip the network address at offset 0) to
+    // `host_count - 2` (skip the broadcast address at `host_count - 1`).
+    let usable = host_count - 2;
+
+    let seed = hash_node_identity(node_identity) % usable;
+
+    for attempt in 0..usable {
+        let offset = 1 + (seed + attempt) % usable;
+        let candidate = u128_to_addr(network_value + offset, bits);
+        if !claimed.contains(&candidate) {
+            return Ok(candidate);
+        }
+    }
+
+    bail!("No addresses remaining in subnet")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network_config::NetworkKey;
+    use std::net::Ipv4Addr;
+
+    fn test_network(network_addr: IpAddr, cidr: u8) -> NetworkConfig {
+        NetworkConfig {
+            name: "TestNetwork".to_string(),
+            key: NetworkKey { access_key: vec![1, 2, 3], secret_key: None },
+            network_addr,
+            cidr,
+            hooks: crate::hooks::HookConfig::default(),
+            transport: crate::transport::TransportKind::default(),
+        }
+    }
+
+    #[test]
+    fn allocate_address_is_deterministic() {
+        let network = test_network(IpAddr::V4(Ipv4Addr::new(192, 168, 251, 0)), 24);
+        let claimed = HashSet::new();
+
+        let first = allocate_address(&network, &[1, 2, 3, 4], &claimed).unwrap();
+        let second = allocate_address(&network, &[1, 2, 3, 4], &claimed).unwrap();
+        assert!(first == second);
+    }
+
+    #[test]
+    fn allocate_address_stays_within_subnet() {
+        let network = test_network(IpAddr::V4(Ipv4Addr::new(192, 168, 251, 0)), 24);
+        let claimed = HashSet::new();
+
+        let addr = allocate_address(&network, &[9, 9, 9], &claimed).unwrap();
+        match addr {
+            IpAddr::V4(v4) => {
+                let octets = v4.octets();
+                assert!(octets[0] == 192 && octets[1] == 168 && octets[2] == 251);
+                assert!(octets[3] != 0 && octets[3] != 255);
+            }
+            IpAddr::V6(_) => panic!("expected an IPv4 address"),
+        }
+    }
+
+    #[test]
+    fn allocate_address_skips_claimed() {
+        let network = test_network(IpAddr::V4(Ipv4Addr::new(192, 168, 251, 0)), 24);
+        let mut claimed = HashSet::new();
+
+        let first = allocate_address(&network, &[4, 5, 6], &claimed).unwrap();
+        claimed.insert(first);
+
+        let second = allocate_address(&network, &[4, 5, 6], &claimed).unwrap();
+        assert!(second != first);
+        assert!(!claimed.contains(&second));
+    }
+
+    #[test]
+    fn allocate_address_supports_ipv6() {
+        let network = test_network("fe80::".parse().unwrap(), 64);
+        let claimed = HashSet::new();
+
+        let addr = allocate_address(&network, &[7, 7, 7], &claimed).unwrap();
+        assert!(addr.is_ipv6());
+    }
+
+    #[test]
+    fn allocate_address_rejects_out_of_range_cidr() {
+        let v4 = test_network(IpAddr::V4(Ipv4Addr::new(192, 168, 251, 0)), 32);
+        assert!(allocate_address(&v4, &[1], &HashSet::new()).is_err());
+
+        let v6 = test_network("fe80::".parse().unwrap(), 0);
+        assert!(allocate_address(&v6, &[1], &HashSet::new()).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn allocate_address_fails_on_full_subnet() {
+        // A /30 only has two usable host addresses; claim both up front.
+        let network = test_network(IpAddr::V4(Ipv4Addr::new(192, 168, 251, 0)), 30);
+        let mut claimed = HashSet::new();
+        claimed.insert(IpAddr::V4(Ipv4Addr::new(192, 168, 251, 1)));
+        claimed.insert(IpAddr::V4(Ipv4Addr::new(192, 168, 251, 2)));
+
+        allocate_address(&network, &[1], &claimed).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! Module for the signed control channel used to administer a network.
+//!
+//! Holders of a `NetworkKey`'s secret key can issue `ControlMessage`s (kicking or whitelisting a
+//! node, changing the network configuration) that any node can authenticate with the access key
+//! alone, via [`NetworkKey::sign`](../network_config/struct.NetworkKey.html) and
+//! [`NetworkKey::verify`](../network_config/struct.NetworkKey.html). Each message carries a
+//! sequence number so that a replayed message, or one delivered out of order, is rejected by
+//! [`ControlChannel`].
+
+extern crate error_chain;
+extern crate serde_json;
+extern crate crypto;
+
+use std::net::IpAddr;
+
+use crypto::ed25519;
+
+use crate::network_config::NetworkKey;
+
+mod errors {
+    error_chain!{}
+}
+
+use errors::*;
+
+/// An administrative action on a network, authorized by the network's secret key.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlMessage {
+    /// Remove a node, identified by its access key, from the network.
+    Kick(Vec<u8>),
+    /// Allow a node, identified by its access key, to join the network.
+    Whitelist(Vec<u8>),
+    /// Change the network's subnet.
+    UpdateConfig {
+        network_addr: IpAddr,
+        cidr: u8,
+    },
+}
+
+/// A `ControlMessage` together with the sequence number it was signed under and its signature.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedControlMessage {
+    /// Strictly increasing per-network counter, used to reject replayed messages.
+    pub sequence: u64,
+    /// The action being authorized.
+    pub message: ControlMessage,
+    /// Ed25519 signature of `(sequence, message)`, produced by the network's secret key.
+    pub signature: Vec<u8>,
+}
+
+/// Canonically serialize `(sequence, message)` into the bytes that get signed.
+fn signed_payload(sequence: u64, message: &ControlMessage) -> Result<Vec<u8>> {
+    serde_json::to_vec(&(sequence, message)).chain_err(|| "Could not serialize control message")
+}
+
+impl NetworkKey {
+    /// Sign a `ControlMessage` under `sequence`, producing a `SignedControlMessage` that any
+    /// holder of the access key can verify with [`NetworkKey::verify`].
+    ///
+    /// Requires this `NetworkKey` to hold a secret key.
+    pub fn sign(&self, message: ControlMessage, sequence: u64) -> Result<SignedControlMessage> {
+        let secret_key = self.secret_key.as_ref().ok_or("Cannot sign without a secret key")?;
+        let payload = signed_payload(sequence, &message)?;
+        let signature = ed25519::signature(&payload, secret_key);
+
+        Ok(SignedControlMessage {
+               sequence,
+               message,
+               signature: signature.to_vec(),
+           })
+    }
+
+    /// Verify that `signed` was produced by this `NetworkKey`'s secret key.
+    ///
+    /// This only checks the signature; replay protection is handled by [`ControlChannel`].
+    pub fn verify(&self, signed: &SignedControlMessage) -> Result<bool> {
+        let payload = signed_payload(signed.sequence, &signed.message)?;
+        Ok(ed25519::verify(&payload, &self.access_key, &signed.signature))
+    }
+}
+
+/// Tracks the last accepted sequence number for a network, so that signed control messages can
+/// be authenticated and replay-protected as they arrive.
+#[derive(Debug, Default)]
+pub struct ControlChannel {
+    last_sequence: Option<u64>,
+}
+
+impl ControlChannel {
+    /// Create a fresh control channel with no messages seen yet.
+    pub fn new() -> ControlChannel {
+        ControlChannel { last_sequence: None }
+    }
+
+    /// Authenticate and replay-check `signed` against `key`, returning its `ControlMessage` if
+    /// accepted.
+    ///
+    /// Rejects `signed` if its signature does not verify under `key.access_key`, or if its
+    /// sequence number is not strictly greater than the last one accepted on this channel.
+    pub fn accept(&mut self, key: &NetworkKey, signed: SignedControlMessage) -> Result<ControlMessage> {
+        if !key.verify(&signed)? {
+            bail!("Control message signature did not verify");
+        }
+
+        if let Some(last_sequence) = self.last_sequence {
+            if signed.sequence <= last_sequence {
+                bail!("Control message sequence {} is not greater than last seen {}", signed.sequence, last_sequence);
+            }
+        }
+
+        self.last_sequence = Some(signed.sequence);
+        Ok(signed.message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network_config::NetworkKey;
+    use crypto::ed25519;
+    use rand::{self, RngCore};
+
+    fn test_keypair() -> NetworkKey {
+        let mut rng = rand::thread_rng();
+        let mut seed: [u8; 32] = [0; 32];
+        rng.fill_bytes(&mut seed);
+        let (secret_key, access_key) = ed25519::keypair(&seed);
+
+        NetworkKey {
+            access_key: access_key.to_vec(),
+            secret_key: Some(secret_key.to_vec()),
+        }
+    }
+
+    #[test]
+    fn sign_and_verify_round_trip() {
+        let key = test_keypair();
+        let signed = key.sign(ControlMessage::Kick(vec![1, 2, 3]), 1).unwrap();
+
+        let access_only = NetworkKey { access_key: key.access_key.clone(), secret_key: None };
+        assert!(access_only.verify(&signed).unwrap());
+    }
+
+    #[test]
+    fn verify_fails_for_wrong_key() {
+        let key = test_keypair();
+        let other = test_keypair();
+        let signed = key.sign(ControlMessage::Whitelist(vec![4, 5, 6]), 1).unwrap();
+
+        assert!(!other.verify(&signed).unwrap());
+    }
+
+    #[test]
+    fn sign_requires_secret_key() {
+        let key = test_keypair();
+        let access_only = NetworkKey { access_key: key.access_key, secret_key: None };
+
+        assert!(access_only.sign(ControlMessage::Kick(vec![1]), 1).is_err());
+    }
+
+    #[test]
+    fn control_channel_accepts_increasing_sequence() {
+        let key = test_keypair();
+        let mut channel = ControlChannel::new();
+
+        let first = key.sign(ControlMessage::Kick(vec![1]), 1).unwrap();
+        assert!(channel.accept(&key, first).is_ok());
+
+        let second = key.sign(ControlMessage::Kick(vec![2]), 2).unwrap();
+        assert!(channel.accept(&key, second).is_ok());
+    }
+
+    #[test]
+    fn control_channel_rejects_replayed_sequence() {
+        let key = test_keypair();
+        let mut channel = ControlChannel::new();
+
+        let first = key.sign(ControlMessage::Kick(vec![1]), 5).unwrap();
+        assert!(channel.accept(&key, first).is_ok());
+
+        let replay = key.sign(ControlMessage::Kick(vec![1]), 5).unwrap();
+        assert!(channel.accept(&key, replay).is_err());
+
+        let stale = key.sign(ControlMessage::Kick(vec![1]), 3).unwrap();
+        assert!(channel.accept(&key, stale).is_err());
+    }
+
+    #[test]
+    fn control_channel_rejects_bad_signature() {
+        let key = test_keypair();
+        let other = test_keypair();
+        let mut channel = ControlChannel::new();
+
+        let mut signed = key.sign(ControlMessage::Kick(vec![1]), 1).unwrap();
+        signed.signature = other.sign(ControlMessage::Kick(vec![1]), 1).unwrap().signature;
+
+        assert!(channel.accept(&key, signed).is_err());
+    }
+}
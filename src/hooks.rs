@@ -0,0 +1,161 @@
+//! Module for user-defined hook scripts fired on connection lifecycle events.
+//!
+//! A [`HookConfig`] carries an optional shell command template for each event Roam can notify
+//! about. When an event fires, the matching template is run through `sh -c` with the event's
+//! details passed as environment variables, letting operators wire up DNS updates, firewall
+//! rules, or logging without patching Roam, similar to vpncloud's hook scripts.
+
+extern crate error_chain;
+extern crate base64;
+
+use std::net::IpAddr;
+use std::process::Command;
+
+mod errors {
+    error_chain!{}
+}
+
+use errors::*;
+
+/// Command templates to run on network lifecycle events.
+///
+/// Every field is optional; an event with no configured template is silently skipped.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookConfig {
+    /// Run when a peer node connects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_connect: Option<String>,
+
+    /// Run when a peer node disconnects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_disconnect: Option<String>,
+
+    /// Run when an address is assigned to a peer node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_address_assigned: Option<String>,
+
+    /// Run when the network configuration changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_config_changed: Option<String>,
+}
+
+impl HookConfig {
+    /// Whether every hook in this config is unset, used to skip serializing an empty
+    /// `HookConfig` so older saved networks round-trip unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.on_connect.is_none() && self.on_disconnect.is_none() && self.on_address_assigned.is_none() &&
+        self.on_config_changed.is_none()
+    }
+}
+
+/// A connection lifecycle event that a hook script can be fired for.
+#[derive(Debug)]
+pub enum HookEvent<'a> {
+    /// A peer node, identified by its access key, connected.
+    ///
+    /// Not fired yet: nothing tracks per-peer connection state until the peer loop exists.
+    #[allow(dead_code)]
+    NodeConnected { access_key: &'a [u8] },
+    /// A peer node, identified by its access key, disconnected.
+    #[allow(dead_code)]
+    NodeDisconnected { access_key: &'a [u8] },
+    /// `address` was assigned to the peer node identified by `access_key`.
+    AddressAssigned { access_key: &'a [u8], address: IpAddr },
+    /// The network configuration changed.
+    ConfigChanged,
+}
+
+impl<'a> HookEvent<'a> {
+    /// The template field of a `HookConfig` that applies to this event.
+    fn template<'b>(&self, config: &'b HookConfig) -> &'b Option<String> {
+        match *self {
+            HookEvent::NodeConnected { .. } => &config.on_connect,
+            HookEvent::NodeDisconnected { .. } => &config.on_disconnect,
+            HookEvent::AddressAssigned { .. } => &config.on_address_assigned,
+            HookEvent::ConfigChanged => &config.on_config_changed,
+        }
+    }
+
+    /// Environment variables describing this event, passed to the spawned hook script.
+    fn env_vars(&self, network_name: &str) -> Vec<(&'static str, String)> {
+        let mut vars = vec![("ROAM_NETWORK_NAME", network_name.to_string())];
+        match *self {
+            HookEvent::NodeConnected { access_key } |
+            HookEvent::NodeDisconnected { access_key } => {
+                vars.push(("ROAM_PEER_ACCESS_KEY", encode_access_key(access_key)));
+            }
+            HookEvent::AddressAssigned { access_key, address } => {
+                vars.push(("ROAM_PEER_ACCESS_KEY", encode_access_key(access_key)));
+                vars.push(("ROAM_ASSIGNED_IP", address.to_string()));
+            }
+            HookEvent::ConfigChanged => {}
+        }
+        vars
+    }
+}
+
+/// Base64-encode an access key for passing through an environment variable.
+fn encode_access_key(access_key: &[u8]) -> String {
+    base64::encode_config(access_key, base64::URL_SAFE_NO_PAD)
+}
+
+/// Run the hook script configured for `event` on `network_name`, if one is configured.
+///
+/// The script is run via `sh -c` with the event's details passed as environment variables. Does
+/// nothing if no template is configured for this event.
+pub fn run_hook(config: &HookConfig, network_name: &str, event: &HookEvent) -> Result<()> {
+    let template = match *event.template(config) {
+        Some(ref template) => template,
+        None => return Ok(()),
+    };
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(template)
+        .envs(event.env_vars(network_name))
+        .status()
+        .chain_err(|| format!("Could not run hook script {:?}", template))?;
+
+    if !status.success() {
+        bail!("Hook script {:?} exited with {}", template, status);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn empty_hook_config_is_empty() {
+        assert!(HookConfig::default().is_empty());
+    }
+
+    #[test]
+    fn hook_config_with_a_template_is_not_empty() {
+        let config = HookConfig { on_connect: Some("true".to_string()), ..HookConfig::default() };
+        assert!(!config.is_empty());
+    }
+
+    #[test]
+    fn run_hook_does_nothing_without_a_template() {
+        let config = HookConfig::default();
+        let event = HookEvent::ConfigChanged;
+        assert!(run_hook(&config, "TestNetwork", &event).is_ok());
+    }
+
+    #[test]
+    fn run_hook_runs_the_configured_command() {
+        let config = HookConfig { on_config_changed: Some("true".to_string()), ..HookConfig::default() };
+        let event = HookEvent::ConfigChanged;
+        assert!(run_hook(&config, "TestNetwork", &event).is_ok());
+    }
+
+    #[test]
+    fn run_hook_fails_on_nonzero_exit() {
+        let config = HookConfig { on_config_changed: Some("false".to_string()), ..HookConfig::default() };
+        let event = HookEvent::ConfigChanged;
+        assert!(run_hook(&config, "TestNetwork", &event).is_err());
+    }
+}
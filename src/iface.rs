@@ -0,0 +1,119 @@
+//! Module for creating and configuring the TUN interface used to carry peer traffic.
+//!
+//! Interface creation goes straight through the kernel's `/dev/net/tun` ioctl, and address/link
+//! state changes go through netlink (via the `rtnetlink` crate), rather than shelling out to
+//! `/sbin/ip`. This keeps `command_connect` from depending on external tooling being installed
+//! on the host.
+
+extern crate error_chain;
+extern crate futures;
+extern crate libc;
+extern crate rtnetlink;
+extern crate tokio;
+
+use std::ffi::CString;
+use std::fs::File;
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::io::FromRawFd;
+
+use futures::stream::TryStreamExt;
+
+mod errors {
+    error_chain!{}
+}
+
+use errors::*;
+
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+#[repr(C)]
+struct IfReq {
+    ifr_name: [libc::c_char; libc::IFNAMSIZ],
+    ifr_flags: libc::c_short,
+    // The kernel's `struct ifreq` is 40 bytes on x86_64 (`ifr_name` plus a union of the
+    // remaining fields); `TUNSETIFF` copies `sizeof(struct ifreq)` bytes out of whatever
+    // pointer we pass, so this struct must match that size or the ioctl reads past `req`.
+    _pad: [u8; 22],
+}
+
+/// A TUN device created for a Roam network, kept open for the lifetime of the connection.
+pub struct TunDevice {
+    pub name: String,
+    // Never read directly, but must be kept alive: dropping it closes the TUN fd and tears
+    // down the interface.
+    #[allow(dead_code)]
+    pub file: File,
+}
+
+/// Create a TUN device with the given interface name.
+pub fn create_tun(name: &str) -> Result<TunDevice> {
+    if name.len() >= libc::IFNAMSIZ {
+        bail!("Interface name {:?} is too long", name);
+    }
+
+    let path = CString::new("/dev/net/tun").chain_err(|| "Invalid /dev/net/tun path")?;
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR) };
+    if fd < 0 {
+        bail!("Could not open /dev/net/tun");
+    }
+
+    let mut req: IfReq = unsafe { mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.as_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    req.ifr_flags = IFF_TUN | IFF_NO_PI;
+
+    let res = unsafe { libc::ioctl(fd, TUNSETIFF, &mut req) };
+    if res < 0 {
+        unsafe { libc::close(fd) };
+        bail!("Could not configure TUN device {:?} via ioctl", name);
+    }
+
+    let file = unsafe { File::from_raw_fd(fd) };
+    Ok(TunDevice { name: name.to_string(), file })
+}
+
+/// Look up the netlink link index for an interface by name.
+async fn link_index(handle: &rtnetlink::Handle, name: &str) -> Result<u32> {
+    let mut links = handle.link().get().set_name_filter(name.to_string()).execute();
+    match links.try_next().await.chain_err(|| format!("Could not look up interface {:?}", name))? {
+        Some(link) => Ok(link.header.index),
+        None => bail!("Interface {:?} not found", name),
+    }
+}
+
+/// Assign an IP address and CIDR prefix length to an interface over netlink.
+pub fn set_addr(name: &str, addr: IpAddr, cidr: u8) -> Result<()> {
+    let mut runtime = tokio::runtime::Runtime::new().chain_err(|| "Could not start async runtime")?;
+    runtime.block_on(async {
+        let (connection, handle, _) = rtnetlink::new_connection().chain_err(|| "Could not open netlink socket")?;
+        tokio::spawn(connection);
+
+        let index = link_index(&handle, name).await?;
+        handle.address()
+            .add(index, addr, cidr)
+            .execute()
+            .await
+            .chain_err(|| format!("Could not assign {}/{} to {:?}", addr, cidr, name))
+    })
+}
+
+/// Bring an interface up over netlink.
+pub fn set_up(name: &str) -> Result<()> {
+    let mut runtime = tokio::runtime::Runtime::new().chain_err(|| "Could not start async runtime")?;
+    runtime.block_on(async {
+        let (connection, handle, _) = rtnetlink::new_connection().chain_err(|| "Could not open netlink socket")?;
+        tokio::spawn(connection);
+
+        let index = link_index(&handle, name).await?;
+        handle.link()
+            .set(index)
+            .up()
+            .execute()
+            .await
+            .chain_err(|| format!("Could not bring {:?} up", name))
+    })
+}
@@ -22,6 +22,9 @@
 //! If you only have the access key, however, you can only connect to the network.
 
 #![recursion_limit = "1024"]
+// error_chain's error_chain!{} expansion references a cfg that predates rustc's check-cfg
+// lint; every module's `mod errors { error_chain!{} }` trips it on current toolchains.
+#![allow(unexpected_cfgs)]
 
 #[macro_use]
 extern crate serde_derive;
@@ -29,10 +32,17 @@ extern crate serde_derive;
 extern crate error_chain;
 extern crate clap;
 extern crate serde;
+extern crate serde_json;
 extern crate rand;
 extern crate crypto;
+extern crate base64;
 
+mod address;
+mod control;
+mod hooks;
+mod iface;
 mod network_config;
+mod transport;
 
 use clap::{App, Arg, SubCommand, AppSettings};
 
@@ -40,6 +50,8 @@ mod errors {
     error_chain!{}
 }
 
+use errors::*;
+
 /// Entry point into Roam. Basically just a Clap interface that calls one of the `command_*` functions.
 fn main() {
     let matches = App::new("Roam")
@@ -47,7 +59,33 @@ fn main() {
         .about("Simple, secure P2P VPN")
         .arg(Arg::with_name("ncurses").short("n").long("ncurses").help("Show an ncurses interface"))
         .subcommand(SubCommand::with_name("new").about("Create a new network"))
-        .subcommand(SubCommand::with_name("connect").about("Connect to an existing network"))
+        .subcommand(SubCommand::with_name("list").about("List saved networks"))
+        .subcommand(SubCommand::with_name("connect")
+                        .about("Connect to an existing network")
+                        .arg(Arg::with_name("name").required(true).help("Name of a previously saved network"))
+                        .arg(Arg::with_name("ws-proxy")
+                                 .long("ws-proxy")
+                                 .takes_value(true)
+                                 .value_name("URL")
+                                 .help("Tunnel peer traffic through a ws:// or wss:// proxy instead of raw UDP"))
+                        .arg(Arg::with_name("peer")
+                                 .long("peer")
+                                 .takes_value(true)
+                                 .value_name("ADDR")
+                                 .help("Peer address to send raw UDP traffic to, when not using --ws-proxy")))
+        .subcommand(SubCommand::with_name("control")
+                        .about("Sign an administrative control message for a network")
+                        .arg(Arg::with_name("name").required(true).help("Name of a previously saved network"))
+                        .arg(Arg::with_name("action")
+                                 .required(true)
+                                 .possible_values(&["kick", "whitelist", "update-config"])
+                                 .help("Action to authorize"))
+                        .arg(Arg::with_name("value")
+                                 .required(true)
+                                 .help("Base64 access key of the node to act on, or <addr>/<cidr> for update-config")))
+        .subcommand(SubCommand::with_name("export")
+                        .about("Export a network as a WireGuard-compatible config file")
+                        .arg(Arg::with_name("name").required(true).help("Name of a previously saved network")))
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::ColoredHelp)
         .setting(AppSettings::DisableVersion)
@@ -55,10 +93,23 @@ fn main() {
         .setting(AppSettings::InferSubcommands)
         .get_matches();
 
-    match matches.subcommand_name() {
-        Some("new") => command_new(),
-        Some("connect") => command_connect(),
-        Some("monitor") => command_monitor(),
+    match matches.subcommand() {
+        ("new", _) => command_new(),
+        ("list", _) => command_list(),
+        ("connect", Some(connect_matches)) => {
+            command_connect(connect_matches.value_of("name").expect("name is required"),
+                             connect_matches.value_of("ws-proxy"),
+                             connect_matches.value_of("peer"))
+        }
+        ("control", Some(control_matches)) => {
+            command_control(control_matches.value_of("name").expect("name is required"),
+                             control_matches.value_of("action").expect("action is required"),
+                             control_matches.value_of("value").expect("value is required"))
+        }
+        ("export", Some(export_matches)) => {
+            command_export(export_matches.value_of("name").expect("name is required"))
+        }
+        ("monitor", _) => command_monitor(),
         _ => {}
     }
 }
@@ -68,7 +119,34 @@ fn command_new() {
     match network_config::new_network_prompt() {
         Ok(network) => {
             println!("Network is {:?}", network);
-            //network_config::save_network_config(&network);
+            if let Err(err) = network_config::save_network_config(&network) {
+                println!("Error: {}", err);
+                for e in err.iter().skip(1) {
+                    println!("Caused by: {}", e);
+                }
+            } else {
+                let changed_event = hooks::HookEvent::ConfigChanged;
+                if let Err(err) = hooks::run_hook(&network.hooks, &network.name, &changed_event) {
+                    println!("Warning: config-changed hook failed: {}", err);
+                }
+            }
+        }
+        Err(err) => {
+            println!("Error: {}", err);
+            for e in err.iter().skip(1) {
+                println!("Caused by: {}", e);
+            }
+        }
+    }
+}
+
+/// Command to list saved networks.
+fn command_list() {
+    match network_config::list_networks() {
+        Ok(names) => {
+            for name in names {
+                println!("{}", name);
+            }
         }
         Err(err) => {
             println!("Error: {}", err);
@@ -79,9 +157,169 @@ fn command_new() {
     }
 }
 
+/// Default UDP port peer traffic is sent and received on.
+const DEFAULT_TRANSPORT_PORT: u16 = 7300;
+
 /// Command to connect to an existing network.
-fn command_connect() {
+fn command_connect(name: &str, ws_proxy: Option<&str>, peer: Option<&str>) {
+    if let Err(err) = try_connect(name, ws_proxy, peer) {
+        println!("Error: {}", err);
+        for e in err.iter().skip(1) {
+            println!("Caused by: {}", e);
+        }
+    }
+}
+
+/// Load `name`'s config, bring up a TUN device for it, assign the network address, and open the
+/// transport peer traffic will be carried over.
+///
+/// `ws_proxy`, if given, overrides the network's configured transport with a WebSocket tunnel to
+/// that proxy URL; otherwise the network's own [`transport::TransportKind`] is used. A UDP
+/// transport is opened to `peer`; a network configured for a WebSocket transport fails to
+/// connect without `ws_proxy`, since the proxy URL only comes from it.
+///
+/// There's no packet-forwarding loop yet, so this doesn't move any traffic between the TUN
+/// device and the transport; it blocks for the lifetime of the process so the interface and
+/// transport it provisions stay up for as long as `roam connect` keeps running, rather than
+/// being torn down the instant this function returns.
+fn try_connect(name: &str, ws_proxy: Option<&str>, peer: Option<&str>) -> errors::Result<()> {
+    let network = network_config::load_network_config(name).chain_err(|| "Could not load network config")?;
+    println!("Connecting to {:?}", network);
+
+    // No peers are tracked locally yet, so this node is the only claim the allocator knows
+    // about; once peer discovery exists, `claimed` should be populated from it.
+    let claimed = std::collections::HashSet::new();
+    let node_identity = network_config::local_node_identity().chain_err(|| "Could not load local node identity")?;
+    let node_addr = address::allocate_address(&network, &node_identity, &claimed)
+        .chain_err(|| "Could not allocate a node address")?;
+
+    let tun = iface::create_tun(&network.name).chain_err(|| "Could not create TUN device")?;
+    iface::set_addr(&tun.name, node_addr, network.cidr).chain_err(|| "Could not assign address")?;
+    iface::set_up(&tun.name).chain_err(|| "Could not bring interface up")?;
+
+    let assigned_event = hooks::HookEvent::AddressAssigned {
+        access_key: &network.key.access_key,
+        address: node_addr,
+    };
+    if let Err(err) = hooks::run_hook(&network.hooks, &network.name, &assigned_event) {
+        println!("Warning: address-assigned hook failed: {}", err);
+    }
+
+    // The transport is opened here and kept alive below for as long as this process runs, so
+    // peer-loop code can pick it up once it exists.
+    let transport_kind = if ws_proxy.is_some() { transport::TransportKind::WebSocket } else { network.transport };
+    let _transport: Box<dyn transport::Transport> = match transport_kind {
+        transport::TransportKind::WebSocket => {
+            let proxy_url = ws_proxy.ok_or("Network is configured for a WebSocket transport; pass --ws-proxy <URL>")?;
+            Box::new(transport::WebSocketTransport::connect(proxy_url).chain_err(|| "Could not open WebSocket transport")?)
+        }
+        transport::TransportKind::Udp => {
+            let peer_addr: std::net::SocketAddr = peer
+                .ok_or("Network uses a UDP transport; pass --peer <ADDR> for the peer to send traffic to")?
+                .parse()
+                .chain_err(|| "Could not parse --peer address")?;
+            // Bind to a real, routable local address rather than `node_addr`: that's the
+            // virtual address assigned to the TUN interface, which only routes to the
+            // network's own subnet and can't source a socket connecting out to `peer_addr`.
+            let bind_addr = match peer_addr {
+                std::net::SocketAddr::V4(_) => std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+                std::net::SocketAddr::V6(_) => std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+            };
+            let local_addr = std::net::SocketAddr::new(bind_addr, DEFAULT_TRANSPORT_PORT);
+            Box::new(transport::UdpTransport::connect(local_addr, peer_addr).chain_err(|| "Could not open UDP transport")?)
+        }
+    };
+
+    println!("Interface {:?} is up; connection will stay active until this process is stopped.", tun.name);
+    // Nothing ever unparks this thread, so this blocks forever: `tun` and `_transport` need to
+    // stay alive for as long as the process runs, or dropping them tears the interface and
+    // transport back down (see the comment on `TunDevice::file`) the instant `try_connect` returns.
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Command to sign an administrative control message with a network's secret key.
+fn command_control(name: &str, action: &str, value: &str) {
+    if let Err(err) = try_control(name, action, value) {
+        println!("Error: {}", err);
+        for e in err.iter().skip(1) {
+            println!("Caused by: {}", e);
+        }
+    }
+}
+
+/// Sign a `kick`/`whitelist`/`update-config` control message against `name`'s network, print it
+/// as JSON, and self-check it against a fresh `ControlChannel` before handing it off.
+///
+/// `value` is a base64 access key for `kick`/`whitelist`, or an `<addr>/<cidr>` subnet for
+/// `update-config`.
+fn try_control(name: &str, action: &str, value: &str) -> errors::Result<()> {
+    let network = network_config::load_network_config(name).chain_err(|| "Could not load network config")?;
+
+    let message = match action {
+        "kick" => {
+            let access_key = base64::decode_config(value, base64::URL_SAFE_NO_PAD).chain_err(|| "Could not decode access key")?;
+            control::ControlMessage::Kick(access_key)
+        }
+        "whitelist" => {
+            let access_key = base64::decode_config(value, base64::URL_SAFE_NO_PAD).chain_err(|| "Could not decode access key")?;
+            control::ControlMessage::Whitelist(access_key)
+        }
+        "update-config" => {
+            let (network_addr, cidr) = network_config::string_to_ip_cidr(value)
+                .chain_err(|| "Could not parse subnet")?
+                .ok_or("A subnet must be given for update-config")?;
+            control::ControlMessage::UpdateConfig { network_addr, cidr }
+        }
+        _ => bail!("Unknown control action {:?}", action),
+    };
+
+    // Nanosecond resolution, rather than whole seconds, keeps two control messages signed
+    // back-to-back from colliding on the same sequence number, which `ControlChannel::accept`
+    // would otherwise reject as a replay.
+    let sequence = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .chain_err(|| "System clock is before the Unix epoch")?
+        .as_nanos() as u64;
+
+    let signed = network.key.sign(message, sequence).chain_err(|| "Could not sign control message")?;
+    let json = serde_json::to_string(&signed).chain_err(|| "Could not serialize signed control message")?;
+
+    // Any node will run this same check on receipt; run it here too so a bad signature is caught
+    // before the message is ever handed to an operator. A fresh channel has seen no earlier
+    // sequence, so this self-check does not reject any sequence number.
+    control::ControlChannel::new()
+        .accept(&network.key, signed)
+        .chain_err(|| "Signed control message failed its own verification")?;
+
+    println!("{}", json);
+    Ok(())
+}
+
+/// Command to export a network as a WireGuard-compatible config file.
+fn command_export(name: &str) {
+    if let Err(err) = try_export(name) {
+        println!("Error: {}", err);
+        for e in err.iter().skip(1) {
+            println!("Caused by: {}", e);
+        }
+    }
+}
+
+/// Load `name`'s config, allocate this node's address within it, and print the resulting
+/// WireGuard `[Interface]`/`[Peer]` config.
+fn try_export(name: &str) -> errors::Result<()> {
+    let network = network_config::load_network_config(name).chain_err(|| "Could not load network config")?;
+
+    let claimed = std::collections::HashSet::new();
+    let node_identity = network_config::local_node_identity().chain_err(|| "Could not load local node identity")?;
+    let node_addr = address::allocate_address(&network, &node_identity, &claimed)
+        .chain_err(|| "Could not allocate a node address")?;
 
+    let config = network.to_wireguard(node_addr).chain_err(|| "Could not export WireGuard config")?;
+    print!("{}", config);
+    Ok(())
 }
 
 /// Command to run an ncurses monitor a connected network.
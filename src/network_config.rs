@@ -3,16 +3,23 @@
 extern crate serde_json;
 extern crate error_chain;
 extern crate base64;
+extern crate dirs;
 
 use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::result::Result as stdResult;
 use std::net::{IpAddr, Ipv4Addr};
 use std::io::prelude::*;
 use std::str::FromStr;
-use rand::{self, Rng};
+use std::fmt;
+use rand::{self, RngCore};
 use serde::{Serializer, Serialize, Deserialize, Deserializer};
 use crypto::ed25519;
 
+use crate::hooks::HookConfig;
+use crate::transport::TransportKind;
+
 mod errors {
     error_chain!{}
 }
@@ -20,7 +27,7 @@ mod errors {
 use errors::*;
 
 /// The internal representation of a network configuration.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct NetworkConfig {
     /// Name of the network.
     ///
@@ -36,6 +43,18 @@ pub struct NetworkConfig {
 
     /// Size of the network mask in CIDR representation.
     pub cidr: u8,
+
+    /// Hook scripts to run on connection lifecycle events for this network.
+    ///
+    /// Absent from older saved configs, in which case it defaults to an empty `HookConfig`.
+    #[serde(default, skip_serializing_if = "HookConfig::is_empty")]
+    pub hooks: HookConfig,
+
+    /// Transport used to carry peer traffic for this network.
+    ///
+    /// Absent from older saved configs, in which case it defaults to [`TransportKind::Udp`].
+    #[serde(default, skip_serializing_if = "TransportKind::is_default")]
+    pub transport: TransportKind,
 }
 
 /// A network key is used to connect to or control a network.
@@ -49,6 +68,7 @@ pub struct NetworkKey {
 
 impl NetworkConfig {
     /// Convert a NetworkConfig to JSON format, to be saved as a config file.
+    #[allow(dead_code)]
     pub fn to_json(&self) -> Result<String> {
         serde_json::to_string(&self).chain_err(|| "Could not serialize network config")
     }
@@ -56,23 +76,158 @@ impl NetworkConfig {
     pub fn to_pretty_json(&self) -> Result<String> {
         serde_json::to_string_pretty(&self).chain_err(|| "Could not serialize network config")
     }
+
+    /// Export this network as a WireGuard-compatible `[Interface]`/`[Peer]` config file, so it
+    /// can be bridged into an existing `wg`/wgconfd setup.
+    ///
+    /// `node_address` is the address this node has been allocated within the network's subnet
+    /// (see [`address::allocate_address`](../address/fn.allocate_address.html)), and is used as
+    /// the interface's `Address`. Unlike [`NetworkKey::to_string`], keys are encoded with
+    /// standard, padded base64, as WireGuard expects.
+    ///
+    /// Requires this network's key to hold a secret key, since the `[Interface]` section needs a
+    /// private key to identify itself with.
+    pub fn to_wireguard(&self, node_address: IpAddr) -> Result<String> {
+        let secret_key = self.key
+            .secret_key
+            .as_ref()
+            .ok_or("Cannot export a WireGuard config without a secret key")?;
+
+        Ok(format!("[Interface]\nPrivateKey = {}\nAddress = {}/{}\n\n[Peer]\nPublicKey = {}\nAllowedIPs = {}/{}\n",
+                   base64::encode(secret_key),
+                   node_address,
+                   self.cidr,
+                   base64::encode(&self.key.access_key),
+                   self.network_addr,
+                   self.cidr))
+    }
+}
+
+/// Returns the directory where network configuration files are stored, creating it if it
+/// doesn't already exist.
+fn config_dir() -> Result<PathBuf> {
+    let mut dir = dirs::config_dir().ok_or("Could not determine config directory")?;
+    dir.push("roam");
+    fs::create_dir_all(&dir).chain_err(|| format!("Could not create config directory {:?}", dir))?;
+    Ok(dir)
+}
+
+/// Reject a network name that isn't safe to use as a single path component under the managed
+/// config directory. Names come from the interactive prompt and from the `name` argument on
+/// `connect`/`control`/`export`, so a name containing a path separator or a `.`/`..` component
+/// would otherwise let `unique_network_name`/`load_network_config` read or write an arbitrary
+/// path instead of a file under `config_dir()`.
+fn validate_network_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\\') {
+        bail!("Network name {:?} is not valid", name);
+    }
+    Ok(())
+}
+
+/// Given a network's requested name, find a name that isn't already taken in `dir`, appending
+/// a numeric suffix as described on [`NetworkConfig::name`](struct.NetworkConfig.html#structfield.name)
+/// if it is.
+fn unique_network_name(dir: &Path, name: &str) -> Result<String> {
+    validate_network_name(name)?;
+
+    if !dir.join(format!("{}.json", name)).exists() {
+        return Ok(name.to_string());
+    }
+
+    let mut suffix = 1;
+    loop {
+        let candidate = format!("{}{}", name, suffix);
+        if !dir.join(format!("{}.json", candidate)).exists() {
+            return Ok(candidate);
+        }
+        suffix += 1;
+    }
 }
 
-impl ToString for NetworkKey {
-    fn to_string(&self) -> String {
+/// Save a `NetworkConfig` as a per-network JSON file under the managed config directory.
+///
+/// If a network with the same name has already been saved, the new file's name is
+/// de-duplicated with a numeric suffix.
+pub fn save_network_config(network: &NetworkConfig) -> Result<()> {
+    let dir = config_dir()?;
+    let name = unique_network_name(&dir, &network.name)?;
+    let path = dir.join(format!("{}.json", name));
+
+    let json = network.to_pretty_json()?;
+    let mut file = fs::File::create(&path).chain_err(|| format!("Could not create {:?}", path))?;
+    file.write_all(json.as_bytes()).chain_err(|| format!("Could not write {:?}", path))?;
+    Ok(())
+}
+
+/// Load a previously saved `NetworkConfig` by name from the managed config directory.
+pub fn load_network_config(name: &str) -> Result<NetworkConfig> {
+    validate_network_name(name)?;
+    let path = config_dir()?.join(format!("{}.json", name));
+
+    let mut file = fs::File::open(&path).chain_err(|| format!("Could not open {:?}", path))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).chain_err(|| format!("Could not read {:?}", path))?;
+
+    serde_json::from_str(&contents).chain_err(|| "Could not deserialize network config")
+}
+
+/// Path to this installation's local node identity file.
+fn node_identity_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("identity"))
+}
+
+/// Load this installation's local node identity, generating and persisting a new random one on
+/// first use.
+///
+/// Unlike a [`NetworkKey`](struct.NetworkKey.html), which is a single secret shared identically
+/// by every node on a network, this identity is local to this installation. It exists purely to
+/// give [`address::allocate_address`](../address/fn.allocate_address.html) a seed that differs
+/// from node to node, so that two nodes on the same network don't derive the same address.
+pub fn local_node_identity() -> Result<Vec<u8>> {
+    let path = node_identity_path()?;
+
+    if path.exists() {
+        let mut file = fs::File::open(&path).chain_err(|| format!("Could not open {:?}", path))?;
+        let mut identity = Vec::new();
+        file.read_to_end(&mut identity).chain_err(|| format!("Could not read {:?}", path))?;
+        return Ok(identity);
+    }
+
+    let mut identity = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut identity);
+
+    let mut file = fs::File::create(&path).chain_err(|| format!("Could not create {:?}", path))?;
+    file.write_all(&identity).chain_err(|| format!("Could not write {:?}", path))?;
+    Ok(identity)
+}
+
+/// List the names of all networks saved in the managed config directory.
+pub fn list_networks() -> Result<Vec<String>> {
+    let dir = config_dir()?;
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&dir).chain_err(|| format!("Could not read config directory {:?}", dir))? {
+        let entry = entry.chain_err(|| "Could not read config directory entry")?;
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+            names.push(stem.to_string());
+        }
+    }
+    Ok(names)
+}
+
+impl fmt::Display for NetworkKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let access_key_base64 = base64::encode_config(&self.access_key, base64::URL_SAFE_NO_PAD);
         match self.secret_key {
             Some(ref sec_key) => {
                 let secret_key_base64 = base64::encode_config(&sec_key[..], base64::URL_SAFE_NO_PAD);
-                {
-                    let mut mut_output_str = String::with_capacity(access_key_base64.len() + secret_key_base64.len() + 1);
-                    mut_output_str.push_str(&access_key_base64);
-                    mut_output_str.push(':');
-                    mut_output_str.push_str(&secret_key_base64);
-                    mut_output_str
-                }
+                write!(f, "{}:{}", access_key_base64, secret_key_base64)
             }
-            _ => access_key_base64
+            _ => write!(f, "{}", access_key_base64)
         }
     }
 }
@@ -85,7 +240,7 @@ impl FromStr for NetworkKey {
             .flat_map(|key| base64::decode_config(key, base64::URL_SAFE_NO_PAD));
         if let Some(access_key) = keys.next() {
             Ok(NetworkKey {
-                access_key: access_key,
+                access_key,
                 secret_key: keys.next()
             })
         } else {
@@ -110,10 +265,10 @@ impl Serialize for NetworkKey {
     }
 }
 
-impl Deserialize for NetworkKey {
+impl<'de> Deserialize<'de> for NetworkKey {
     /// Deserialize a colon-separated string into a NetworkKey.
     fn deserialize<D>(deserializer: D) -> stdResult<Self, D::Error>
-        where D: Deserializer
+        where D: Deserializer<'de>
     {
         use serde::de::Error;
 
@@ -179,9 +334,7 @@ pub fn new_network_prompt() -> Result<NetworkConfig> {
     println!("To set up your network, we need to ask a few questions first.");
 
     let name = question_prompt("What should this network be called?")?;
-    if name.is_empty() {
-        bail!("A network name needs to be provided.");
-    }
+    validate_network_name(&name).chain_err(|| "A network name needs to be provided, and cannot contain a path separator or be . or ..")?;
 
     let ip_cidr = question_prompt("What subnet should be used for this network? (or leave blank for 192.168.251.0/24)")?;
 
@@ -190,16 +343,49 @@ pub fn new_network_prompt() -> Result<NetworkConfig> {
     let network_key = generate_secret_key();
 
     Ok(NetworkConfig {
-           name: name,
+           name,
            network_addr: ip_addr,
            key: network_key,
-           cidr: cidr,
+           cidr,
+           hooks: HookConfig::default(),
+           transport: TransportKind::default(),
        })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::env;
+
+    #[test]
+    fn unique_network_name_avoids_existing_files() {
+        let dir = env::temp_dir().join("roam_test_unique_network_name");
+        fs::create_dir_all(&dir).unwrap();
+        fs::File::create(dir.join("home.json")).unwrap();
+        fs::File::create(dir.join("home1.json")).unwrap();
+
+        assert!(unique_network_name(&dir, "home").unwrap() == "home2");
+        assert!(unique_network_name(&dir, "office").unwrap() == "office");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unique_network_name_rejects_path_traversal() {
+        let dir = env::temp_dir().join("roam_test_unique_network_name_traversal");
+
+        assert!(unique_network_name(&dir, "../../../../tmp/roam_traversal_poc").is_err());
+        assert!(unique_network_name(&dir, "..").is_err());
+        assert!(unique_network_name(&dir, ".").is_err());
+        assert!(unique_network_name(&dir, "a/b").is_err());
+        assert!(unique_network_name(&dir, "a\\b").is_err());
+        assert!(unique_network_name(&dir, "").is_err());
+    }
+
+    #[test]
+    fn load_network_config_rejects_path_traversal() {
+        assert!(load_network_config("../../../../tmp/roam_traversal_poc").is_err());
+    }
 
     #[test]
     fn serialize_network_key() {
@@ -281,7 +467,9 @@ mod test {
             name: "TestName".to_string(),
             key: access_network_key,
             network_addr: ip,
-            cidr: cidr
+            cidr,
+            hooks: HookConfig::default(),
+            transport: TransportKind::default(),
         };
         let json = input.to_json().unwrap();
         let expected_json = r#"{"name":"TestName","key":"accs","network_addr":"192.168.1.1","cidr":24}"#;
@@ -296,11 +484,48 @@ mod test {
             name: "TestName".to_string(),
             key: access_network_key,
             network_addr: ip,
-            cidr: cidr
+            cidr,
+            hooks: HookConfig::default(),
+            transport: TransportKind::default(),
         };
         let json = input.to_json().unwrap();
         println!("{}", json);
         let expected_json = r#"{"name":"TestName","key":"accs","network_addr":"fe80::1","cidr":64}"#;
         assert!(json == expected_json);
     }
+
+    #[test]
+    fn to_wireguard_requires_a_secret_key() {
+        let network_key = NetworkKey { access_key: vec![105, 199, 44], secret_key: None };
+        let network = NetworkConfig {
+            name: "TestName".to_string(),
+            key: network_key,
+            network_addr: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)),
+            cidr: 24,
+            hooks: HookConfig::default(),
+            transport: TransportKind::default(),
+        };
+
+        assert!(network.to_wireguard(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5))).is_err());
+    }
+
+    #[test]
+    fn to_wireguard_emits_interface_and_peer_sections() {
+        let network_key = NetworkKey {
+            access_key: vec![105, 199, 44],
+            secret_key: Some(vec![177, 202, 237]),
+        };
+        let network = NetworkConfig {
+            name: "TestName".to_string(),
+            key: network_key,
+            network_addr: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)),
+            cidr: 24,
+            hooks: HookConfig::default(),
+            transport: TransportKind::default(),
+        };
+
+        let config = network.to_wireguard(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5))).unwrap();
+        let expected = "[Interface]\nPrivateKey = scrt\nAddress = 192.168.1.5/24\n\n[Peer]\nPublicKey = accs\nAllowedIPs = 192.168.1.0/24\n";
+        assert!(config == expected);
+    }
 }
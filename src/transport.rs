@@ -0,0 +1,146 @@
+//! Module for the pluggable transport that carries peer traffic.
+//!
+//! Roam normally exchanges packets directly over UDP, but that doesn't work on networks that
+//! only permit outbound HTTP(S). [`WebSocketTransport`] wraps the same traffic in a WebSocket
+//! connection to a proxy endpoint that forwards it on to the real peer, mirroring vpncloud's
+//! websocket proxy mode, so a node behind such a firewall can still reach the mesh.
+
+extern crate error_chain;
+extern crate tungstenite;
+extern crate url;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::net::TcpStream;
+
+use self::tungstenite::{Message, WebSocket};
+use self::tungstenite::stream::MaybeTlsStream;
+
+mod errors {
+    error_chain!{}
+}
+
+use errors::*;
+
+/// Which [`Transport`] implementation a network should use to carry peer traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// Send peer traffic directly over UDP.
+    #[default]
+    Udp,
+    /// Tunnel peer traffic through a WebSocket proxy.
+    WebSocket,
+}
+
+impl TransportKind {
+    /// Whether this is the default transport, used to skip serializing it on older configs.
+    pub fn is_default(&self) -> bool {
+        *self == TransportKind::default()
+    }
+}
+
+/// A transport capable of sending and receiving a peer's raw packets.
+///
+/// Not yet called outside of construction: `try_connect` opens and holds one for the rest of
+/// the connection so the peer loop can start using it once that exists.
+#[allow(dead_code)]
+pub trait Transport {
+    /// Send a single packet to the peer.
+    fn send(&mut self, packet: &[u8]) -> Result<()>;
+
+    /// Receive a single packet from the peer, returning its length.
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize>;
+}
+
+/// The default transport: peer packets sent directly over a UDP socket.
+pub struct UdpTransport {
+    #[allow(dead_code)]
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Bind a UDP socket on `local_addr` and target `peer_addr` for all traffic.
+    pub fn connect(local_addr: SocketAddr, peer_addr: SocketAddr) -> Result<UdpTransport> {
+        let socket = UdpSocket::bind(local_addr).chain_err(|| format!("Could not bind UDP socket on {}", local_addr))?;
+        socket.connect(peer_addr).chain_err(|| format!("Could not connect UDP socket to {}", peer_addr))?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) -> Result<()> {
+        self.socket.send(packet).chain_err(|| "Could not send UDP packet")?;
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.socket.recv(buf).chain_err(|| "Could not receive UDP packet")
+    }
+}
+
+/// A transport that tunnels peer packets through a WebSocket proxy, for nodes on networks that
+/// only permit outbound HTTP(S).
+pub struct WebSocketTransport {
+    #[allow(dead_code)]
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+}
+
+impl WebSocketTransport {
+    /// Connect to a `ws://` or `wss://` proxy endpoint that will forward traffic to the peer.
+    pub fn connect(proxy_url: &str) -> Result<WebSocketTransport> {
+        let url = url::Url::parse(proxy_url).chain_err(|| format!("Could not parse proxy URL {:?}", proxy_url))?;
+        let (socket, _response) = tungstenite::connect(url).chain_err(|| format!("Could not connect to proxy {:?}", proxy_url))?;
+        Ok(WebSocketTransport { socket })
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send(&mut self, packet: &[u8]) -> Result<()> {
+        self.socket
+            .write_message(Message::Binary(packet.to_vec()))
+            .chain_err(|| "Could not send WebSocket message")
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.socket.read_message().chain_err(|| "Could not read WebSocket message")? {
+                Message::Binary(data) => {
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    return Ok(len);
+                }
+                // Pings, pongs, and other control frames are handled by tungstenite itself;
+                // anything else carries no peer data, so keep waiting for the next message.
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transport_kind_defaults_to_udp() {
+        assert!(TransportKind::default() == TransportKind::Udp);
+        assert!(TransportKind::default().is_default());
+        assert!(!TransportKind::WebSocket.is_default());
+    }
+
+    #[test]
+    fn udp_transport_round_trips_a_packet() {
+        let mut a = UdpTransport::connect("127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()).unwrap();
+        let local_addr = a.socket.local_addr().unwrap();
+
+        let mut b = UdpTransport::connect("127.0.0.1:0".parse().unwrap(), local_addr).unwrap();
+        let b_addr = b.socket.local_addr().unwrap();
+        a.socket.connect(b_addr).unwrap();
+
+        a.send(b"hello").unwrap();
+
+        let mut buf = [0u8; 16];
+        let len = b.recv(&mut buf).unwrap();
+        assert!(&buf[..len] == b"hello");
+    }
+}